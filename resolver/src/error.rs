@@ -0,0 +1,128 @@
+
+// Copyright 2015-2017 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Error types returned while resolving a `Lookup`.
+
+use std::fmt;
+use std::io;
+
+use trust_dns::op::{Query, ResponseCode};
+
+/// No records were found for `query`. `response_code` distinguishes a name that simply
+/// doesn't exist (`NXDomain`) from one that exists but has no records of the requested type
+/// (`NoError` with an empty answer, commonly called "NoData"). `soa_ttl`, when present, is the
+/// authority section's SOA minimum TTL, suitable for negative caching.
+///
+/// This is its own `Error` type, rather than being inlined into `ResolveErrorKind`, so that
+/// `DnsLru` (which sees the raw `Message` the classification is derived from) can hand it back
+/// as an `io::Error` without losing the detail: `ResolveError`'s `From<io::Error>` downcasts
+/// the `io::Error`'s inner error back into this type when present.
+#[derive(Debug, Clone)]
+pub struct NoRecordsFound {
+    pub query: Query,
+    pub response_code: ResponseCode,
+    pub soa_ttl: Option<u32>,
+}
+
+impl fmt::Display for NoRecordsFound {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "no records found for {} (response code: {})",
+            self.query,
+            self.response_code
+        )
+    }
+}
+
+impl ::std::error::Error for NoRecordsFound {
+    fn description(&self) -> &str {
+        "no records found"
+    }
+}
+
+/// The error kinds that can occur while performing a lookup.
+#[derive(Debug)]
+pub enum ResolveErrorKind {
+    /// No records were found for the query; see `NoRecordsFound`.
+    NoRecordsFound(NoRecordsFound),
+    /// An I/O error occurred while performing the lookup.
+    Io(io::Error),
+    /// An ad hoc error message.
+    Message(String),
+}
+
+/// The error type for errors that get returned when performing a lookup.
+#[derive(Debug)]
+pub struct ResolveError {
+    kind: ResolveErrorKind,
+}
+
+impl ResolveError {
+    pub fn kind(&self) -> &ResolveErrorKind {
+        &self.kind
+    }
+
+    /// `true` if this error represents an NXDOMAIN response.
+    pub fn is_nx_domain(&self) -> bool {
+        match self.kind {
+            ResolveErrorKind::NoRecordsFound(ref e) => e.response_code == ResponseCode::NXDomain,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            ResolveErrorKind::NoRecordsFound(ref e) => write!(f, "{}", e),
+            ResolveErrorKind::Io(ref e) => write!(f, "io error: {}", e),
+            ResolveErrorKind::Message(ref msg) => f.write_str(msg),
+        }
+    }
+}
+
+impl ::std::error::Error for ResolveError {
+    fn description(&self) -> &str {
+        match self.kind {
+            ResolveErrorKind::NoRecordsFound(..) => "no records found",
+            ResolveErrorKind::Io(..) => "io error",
+            ResolveErrorKind::Message(ref msg) => msg,
+        }
+    }
+}
+
+impl From<ResolveErrorKind> for ResolveError {
+    fn from(kind: ResolveErrorKind) -> Self {
+        ResolveError { kind }
+    }
+}
+
+impl From<io::Error> for ResolveError {
+    fn from(e: io::Error) -> Self {
+        // `DnsLru` reports a negative lookup as a `NoRecordsFound` boxed inside an `io::Error`
+        // (to match the pre-existing `DnsLru::lookup` signature); recover it here instead of
+        // flattening it into an opaque message, so `is_nx_domain`/`soa_ttl` keep working.
+        let no_records = e.get_ref()
+            .and_then(|inner| inner.downcast_ref::<NoRecordsFound>())
+            .cloned();
+        match no_records {
+            Some(no_records) => ResolveErrorKind::NoRecordsFound(no_records).into(),
+            None => ResolveErrorKind::Io(e).into(),
+        }
+    }
+}
+
+impl From<ResolveError> for io::Error {
+    fn from(e: ResolveError) -> Self {
+        match e.kind {
+            ResolveErrorKind::Io(e) => e,
+            other => io::Error::new(io::ErrorKind::Other, format!("{}", ResolveError { kind: other })),
+        }
+    }
+}
@@ -11,6 +11,7 @@
 use std::error::Error;
 use std::io;
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::slice::Iter;
 use std::sync::Arc;
 
@@ -21,8 +22,10 @@ use trust_dns::error::ClientError;
 use trust_dns::op::{Message, Query};
 use trust_dns::rr::{Name, RecordType, RData};
 
+use error::{ResolveError, ResolveErrorKind};
 use lru::DnsLru;
 use name_server_pool::NameServerPool;
+use recursor::RecursorHandle;
 
 /// Result of a DNS query when querying for any record type supported by the TRust-DNS Client library.
 ///
@@ -77,6 +80,8 @@ impl<'a> Iterator for LookupIter<'a> {
 pub enum LookupEither {
     Retry(RetryClientHandle<NameServerPool>),
     Secure(SecureClientHandle<RetryClientHandle<NameServerPool>>),
+    /// Resolves by walking delegations from root hints, instead of forwarding to a pool.
+    Recursive(RecursorHandle),
 }
 
 impl ClientHandle for LookupEither {
@@ -84,6 +89,7 @@ impl ClientHandle for LookupEither {
         match *self {
             LookupEither::Retry(ref mut c) => c.send(message),
             LookupEither::Secure(ref mut c) => c.send(message),
+            LookupEither::Recursive(ref mut c) => c.send(message),
         }
     }
 }
@@ -97,7 +103,11 @@ pub struct InnerLookupFuture<C: ClientHandle + 'static> {
     client_cache: DnsLru<C>,
     names: Vec<Name>,
     record_type: RecordType,
-    future: Box<Future<Item = Lookup, Error = io::Error>>,
+    future: Box<Future<Item = Lookup, Error = ResolveError>>,
+    chain: LookupStack,
+    chased: Option<Lookup>,
+    current: Name,
+    max_chain_depth: usize,
 }
 
 impl<C: ClientHandle + 'static> InnerLookupFuture<C> {
@@ -115,21 +125,43 @@ impl<C: ClientHandle + 'static> InnerLookupFuture<C> {
     ) -> Self {
         let name = names.pop().expect("can not lookup IPs for no names");
 
-        let query = lookup(name, record_type, client_cache);
+        let mut chain = LookupStack::new();
+        // the initial query can never collide with itself
+        let _ = chain.push(Query::query(name.clone(), record_type));
+
+        let query = lookup(name.clone(), record_type, client_cache);
         InnerLookupFuture {
             client_cache: client_cache.clone(),
             names,
             record_type,
             future: Box::new(query),
+            chain,
+            chased: None,
+            current: name,
+            max_chain_depth: MAX_CNAME_CHAIN_DEPTH,
         }
     }
 
-    fn next_lookup<F: FnOnce() -> Poll<Lookup, io::Error>>(
+    /// Overrides the maximum CNAME/DNAME chain length (default `MAX_CNAME_CHAIN_DEPTH`)
+    /// `chase_cname` will follow before giving up and falling through to the next candidate
+    /// name, bounding work on malicious or looping chains.
+    pub(crate) fn with_max_chain_depth(mut self, max_chain_depth: usize) -> Self {
+        self.max_chain_depth = max_chain_depth;
+        self
+    }
+
+    fn next_lookup<F: FnOnce() -> Poll<Lookup, ResolveError>>(
         &mut self,
         otherwise: F,
-    ) -> Poll<Lookup, io::Error> {
+    ) -> Poll<Lookup, ResolveError> {
         let name = self.names.pop();
         if let Some(name) = name {
+            // starting over on a new candidate name resets the CNAME chain
+            self.chain = LookupStack::new();
+            self.chased = None;
+            let _ = self.chain.push(Query::query(name.clone(), self.record_type));
+            self.current = name.clone();
+
             let query = lookup(name, self.record_type, &mut self.client_cache);
 
             mem::replace(&mut self.future, Box::new(query));
@@ -141,6 +173,39 @@ impl<C: ClientHandle + 'static> InnerLookupFuture<C> {
         }
     }
 
+    /// Follows a CNAME/DNAME found in `lookup_ip` by issuing a follow-up query for the
+    /// canonical target, accumulating the alias trail so it can be prefixed to the final
+    /// answer. Returns `None` if `lookup_ip` contains no alias to chase.
+    fn chase_cname(&mut self, lookup_ip: Lookup) -> Option<Poll<Lookup, ResolveError>> {
+        let target = cname_target(&lookup_ip)?;
+
+        if self.chain.len() >= self.max_chain_depth {
+            return Some(self.next_lookup(|| {
+                Err(
+                    ResolveErrorKind::Message(
+                        "maximum CNAME chain length exceeded".to_string(),
+                    ).into(),
+                )
+            }));
+        }
+
+        let query = Query::query(target.clone(), self.record_type);
+        if let Err(e) = self.chain.push(query) {
+            return Some(self.next_lookup(|| Err(e.into())));
+        }
+
+        self.chased = Some(match self.chased.take() {
+            Some(chased) => chased.append(lookup_ip),
+            None => lookup_ip,
+        });
+
+        self.current = target.clone();
+        let query = lookup(target, self.record_type, &mut self.client_cache);
+        mem::replace(&mut self.future, Box::new(query));
+        task::current().notify();
+        Some(Ok(Async::NotReady))
+    }
+
     pub(crate) fn error<E: Error>(client_cache: DnsLru<C>, error: E) -> Self {
         return InnerLookupFuture {
             // errors on names don't need to be cheap... i.e. this clone is unfortunate in this case.
@@ -148,24 +213,55 @@ impl<C: ClientHandle + 'static> InnerLookupFuture<C> {
             names: vec![],
             record_type: RecordType::NULL,
             future: Box::new(future::err(
-                io::Error::new(io::ErrorKind::Other, format!("{}", error)),
+                ResolveErrorKind::Message(format!("{}", error)).into(),
             )),
+            chain: LookupStack::new(),
+            chased: None,
+            current: Name::root(),
+            max_chain_depth: MAX_CNAME_CHAIN_DEPTH,
         };
     }
+
+    /// `DnsLru` classifies an empty answer as a typed `NoRecordsFound` error before it ever
+    /// reaches here (see `lru::classify`), so the only empty `Lookup` seen here is the one
+    /// `maybe_ip_lookup` synthesizes for an IP literal of the other address family.
+    fn no_records_found(&mut self) -> Poll<Lookup, ResolveError> {
+        let err = ResolveErrorKind::Message(format!(
+            "no records found for {}",
+            Query::query(self.current.clone(), self.record_type)
+        ));
+        self.next_lookup(|| Err(err.into()))
+    }
 }
 
 impl<C: ClientHandle + 'static> Future for InnerLookupFuture<C> {
     type Item = Lookup;
-    type Error = io::Error;
+    type Error = ResolveError;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match self.future.poll() {
             Ok(Async::Ready(lookup_ip)) => {
                 if lookup_ip.rdatas.len() == 0 {
-                    return self.next_lookup(|| Ok(Async::Ready(lookup_ip)));
-                } else {
-                    return Ok(Async::Ready(lookup_ip));
+                    return self.no_records_found();
+                }
+
+                if lookup_ip.iter().any(
+                    |r| is_requested_type(r, self.record_type),
+                )
+                {
+                    let result = match self.chased.take() {
+                        Some(chased) => chased.append(lookup_ip),
+                        None => lookup_ip,
+                    };
+                    return Ok(Async::Ready(result));
                 }
+
+                if let Some(poll) = self.chase_cname(lookup_ip) {
+                    return poll;
+                }
+
+                // no records of the requested type, and nothing to chase
+                self.no_records_found()
             }
             p @ Ok(Async::NotReady) => p,
             e @ Err(_) => {
@@ -175,41 +271,116 @@ impl<C: ClientHandle + 'static> Future for InnerLookupFuture<C> {
     }
 }
 
-/// Queries for the specified record type
+/// Queries for the specified record type, short-circuiting network traffic entirely when
+/// `name` turns out to be an IP literal rather than a hostname.
 fn lookup<C: ClientHandle + 'static>(
     name: Name,
     record_type: RecordType,
     client_cache: &mut DnsLru<C>,
-) -> Box<Future<Item = Lookup, Error = io::Error>> {
-    client_cache.lookup(Query::query(name, record_type))
+) -> Box<Future<Item = Lookup, Error = ResolveError>> {
+    if let Some(lookup) = maybe_ip_lookup(&name, record_type) {
+        return Box::new(future::ok(lookup));
+    }
+
+    Box::new(client_cache.lookup(Query::query(name, record_type)).from_err())
 }
 
-// TODO: maximum recursion on CNAME, etc, chains...
-// struct LookupStack(Vec<Query>);
+/// If `name` is actually an IP literal (e.g. `127.0.0.1` or `::1`) rather than a hostname,
+/// synthesizes the `Lookup` it would have resolved to, instead of sending it over the wire.
+///
+/// Respects `record_type`: an IPv4 literal queried for `AAAA` (or vice versa) yields an empty
+/// `Lookup`, without a query being sent, so `InnerLookupFuture` falls through to the next
+/// candidate name. Returns `None` only if `name` is not an IP literal. Exposed so higher-level IP resolvers can opt into this behavior, matching
+/// the `TryParseIp` handling expected by downstream consumers.
+pub(crate) fn maybe_ip_lookup(name: &Name, record_type: RecordType) -> Option<Lookup> {
+    let mut text = name.to_utf8();
+    if text.ends_with('.') {
+        text.pop();
+    }
 
-// impl LookupStack {
-//     // pushes the Query onto the stack, and returns a reference. An error will be returned
-//     fn push(&mut self, query: Query) -> io::Result<&Query> {
-//         if self.0.contains(&query) {
-//             return Err(io::Error::new(io::ErrorKind::Other, "circular CNAME or other recursion"));
-//         }
+    if let Ok(ip) = text.parse::<Ipv4Addr>() {
+        return match record_type {
+            RecordType::A => Some(Lookup::new(Arc::new(vec![RData::A(ip)]))),
+            _ => Some(Lookup::new(Arc::new(vec![]))),
+        };
+    }
 
-//         self.0.push(query);
-//         Ok(self.0.last().unwrap())
-//     }
-// }
+    if let Ok(ip) = text.parse::<Ipv6Addr>() {
+        return match record_type {
+            RecordType::AAAA => Some(Lookup::new(Arc::new(vec![RData::AAAA(ip)]))),
+            _ => Some(Lookup::new(Arc::new(vec![]))),
+        };
+    }
+
+    None
+}
+
+/// `true` if `rdata` should satisfy a lookup for `record_type`.
+///
+/// CNAME and DNAME answers are aliases, not answers in their own right, unless the caller
+/// actually asked for a CNAME/DNAME record; everything else is taken at face value.
+fn is_requested_type(rdata: &RData, record_type: RecordType) -> bool {
+    match *rdata {
+        RData::CNAME(..) => record_type == RecordType::CNAME,
+        RData::DNAME(..) => record_type == RecordType::DNAME,
+        _ => true,
+    }
+}
+
+/// Returns the canonical name target of the first CNAME or DNAME found in `lookup`, if any.
+fn cname_target(lookup: &Lookup) -> Option<Name> {
+    lookup
+        .iter()
+        .filter_map(|r| match *r {
+            RData::CNAME(ref name) | RData::DNAME(ref name) => Some(name.clone()),
+            _ => None,
+        })
+        .next()
+}
+
+/// Maximum length of the query chain (the initial query plus each CNAME/DNAME indirection
+/// followed, so at most 7 indirections) for a single candidate name before giving up and
+/// falling through to the next one, to bound work on malicious chains.
+const MAX_CNAME_CHAIN_DEPTH: usize = 8;
+
+/// Tracks the `Query`s issued while following a CNAME/DNAME chain so that loops can be
+/// detected instead of recursing forever.
+struct LookupStack(Vec<Query>);
+
+impl LookupStack {
+    fn new() -> Self {
+        LookupStack(Vec::new())
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    // pushes the Query onto the stack, and returns a reference. An error will be returned
+    fn push(&mut self, query: Query) -> io::Result<&Query> {
+        if self.0.contains(&query) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "circular CNAME or other recursion",
+            ));
+        }
+
+        self.0.push(query);
+        Ok(self.0.last().unwrap())
+    }
+}
 
 
 #[cfg(test)]
 pub mod tests {
-    use std::net::{IpAddr, Ipv4Addr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
     use std::sync::{Arc, Mutex};
 
     use futures::{future, Future};
 
     use trust_dns::client::ClientHandle;
     use trust_dns::error::*;
-    use trust_dns::op::Message;
+    use trust_dns::op::{Message, ResponseCode};
     use trust_dns::rr::{Name, Record, RData, RecordType};
 
     use super::*;
@@ -240,10 +411,24 @@ pub mod tests {
         Ok(message)
     }
 
+    pub fn cname_message(name: Name, target: Name) -> ClientResult<Message> {
+        let mut message = Message::new();
+        message.insert_answers(vec![
+            Record::from_rdata(name, 86400, RecordType::CNAME, RData::CNAME(target)),
+        ]);
+        Ok(message)
+    }
+
     pub fn empty() -> ClientResult<Message> {
         Ok(Message::new())
     }
 
+    pub fn nxdomain() -> ClientResult<Message> {
+        let mut message = Message::new();
+        message.set_response_code(ResponseCode::NXDomain);
+        Ok(message)
+    }
+
     pub fn error() -> ClientResult<Message> {
         Err(ClientErrorKind::Io.into())
     }
@@ -282,17 +467,181 @@ pub mod tests {
 
     #[test]
     fn test_empty_no_response() {
+        // an empty answer section is now a typed NoRecordsFound error rather than an empty
+        // Lookup; see test_no_records_found_is_a_typed_error for the response code it carries.
+        let err = lookup(
+            Name::root(),
+            RecordType::A,
+            &mut DnsLru::new(0, mock(vec![empty()])),
+        ).wait()
+            .unwrap_err();
+
+        match *err.kind() {
+            ResolveErrorKind::NoRecordsFound(..) => {}
+            ref other => panic!("expected NoRecordsFound, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_cname_chase() {
+        let target = Name::parse("www.example.com.", None).unwrap();
+
+        // messages are popped in reverse order: the initial query answers with a CNAME,
+        // and the follow-up query for the canonical target answers with the A record.
+        let messages = vec![v4_message(), cname_message(Name::root(), target)];
+
+        let lookup = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::A,
+            &mut DnsLru::new(0, mock(messages)),
+        ).wait()
+            .unwrap();
+
         assert_eq!(
-            lookup(
-                Name::root(),
+            lookup
+                .iter()
+                .filter_map(|r| r.to_ip_addr())
+                .collect::<Vec<IpAddr>>(),
+            vec![Ipv4Addr::new(127, 0, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn test_ip_literal_short_circuits_lookup() {
+        let name = Name::parse("127.0.0.1", None).unwrap();
+
+        // no messages queued: if the network were touched, `unwrap_or(empty())` would kick
+        // in and produce no records, instead of the synthesized literal IP below.
+        let lookup = lookup(
+            name,
+            RecordType::A,
+            &mut DnsLru::new(0, mock(vec![])),
+        ).wait()
+            .unwrap();
+
+        assert_eq!(
+            lookup.iter().filter_map(|r| r.to_ip_addr()).collect::<Vec<IpAddr>>(),
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_literal_short_circuits_lookup() {
+        let name = Name::parse("::1", None).unwrap();
+
+        // no messages queued: if the network were touched, `unwrap_or(empty())` would kick
+        // in and produce no records, instead of the synthesized literal IP below.
+        let lookup = lookup(
+            name,
+            RecordType::AAAA,
+            &mut DnsLru::new(0, mock(vec![])),
+        ).wait()
+            .unwrap();
+
+        assert_eq!(
+            lookup.iter().filter_map(|r| r.to_ip_addr()).collect::<Vec<IpAddr>>(),
+            vec![IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_literal_mismatched_record_type_falls_through() {
+        let name = Name::parse("::1", None).unwrap();
+
+        // an IPv6 literal queried for A yields an empty Lookup without touching the network,
+        // and with no other candidate name to fall through to, the lookup fails.
+        assert!(
+            InnerLookupFuture::lookup(
+                vec![name],
                 RecordType::A,
                 &mut DnsLru::new(0, mock(vec![empty()])),
             ).wait()
-                .unwrap()
-                .iter()
-                .map(|r| r.to_ip_addr().unwrap())
-                .collect::<Vec<IpAddr>>(),
-            Vec::<IpAddr>::new()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_ip_literal_mismatched_record_type_falls_through() {
+        let name = Name::parse("127.0.0.1", None).unwrap();
+
+        // an IPv4 literal queried for AAAA yields an empty Lookup without touching the network,
+        // and with no other candidate name to fall through to, the lookup fails.
+        assert!(
+            InnerLookupFuture::lookup(
+                vec![name],
+                RecordType::AAAA,
+                &mut DnsLru::new(0, mock(vec![empty()])),
+            ).wait()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_cname_loop_is_an_error() {
+        // the CNAME points right back at the name that was just queried
+        let messages = vec![cname_message(Name::root(), Name::root())];
+
+        assert!(
+            InnerLookupFuture::lookup(
+                vec![Name::root()],
+                RecordType::A,
+                &mut DnsLru::new(0, mock(messages)),
+            ).wait()
+                .is_err()
         );
     }
+
+    #[test]
+    fn test_cname_chain_depth_is_configurable() {
+        // a single CNAME hop, which the default MAX_CNAME_CHAIN_DEPTH would happily follow,
+        // but an overridden depth of 1 (the length of the chain before the hop is even
+        // chased) should reject outright.
+        let target = Name::parse("a.example.com.", None).unwrap();
+        let messages = vec![cname_message(Name::root(), target)];
+
+        let future = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::A,
+            &mut DnsLru::new(0, mock(messages)),
+        ).with_max_chain_depth(1);
+
+        assert!(future.wait().is_err());
+    }
+
+    #[test]
+    fn test_no_records_found_is_a_typed_error() {
+        let err = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::A,
+            &mut DnsLru::new(0, mock(vec![empty()])),
+        ).wait()
+            .unwrap_err();
+
+        match *err.kind() {
+            ResolveErrorKind::NoRecordsFound(ref e) => {
+                assert_eq!(e.query.query_type(), RecordType::A);
+                assert_eq!(e.response_code, ResponseCode::NoError);
+            }
+            ref other => panic!("expected NoRecordsFound, got {:?}", other),
+        }
+        assert!(!err.is_nx_domain());
+    }
+
+    #[test]
+    fn test_nxdomain_is_distinguished_from_nodata() {
+        let err = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::A,
+            &mut DnsLru::new(0, mock(vec![nxdomain()])),
+        ).wait()
+            .unwrap_err();
+
+        assert!(err.is_nx_domain());
+        match *err.kind() {
+            ResolveErrorKind::NoRecordsFound(ref e) => {
+                assert_eq!(e.response_code, ResponseCode::NXDomain);
+            }
+            ref other => panic!("expected NoRecordsFound, got {:?}", other),
+        }
+    }
 }
\ No newline at end of file
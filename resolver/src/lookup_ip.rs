@@ -0,0 +1,221 @@
+
+// Copyright 2015-2017 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! IP resolution built on top of the generic `Lookup`/`InnerLookupFuture` machinery, adding
+//! control over whether A, AAAA, or both are queried, and in what order.
+
+use futures::{Future, Poll};
+
+use trust_dns::client::ClientHandle;
+use trust_dns::rr::{Name, RecordType};
+
+use error::{ResolveError, ResolveErrorKind};
+use lookup::{InnerLookupFuture, Lookup};
+use lru::DnsLru;
+
+/// Controls which of A and AAAA are queried for an IP lookup, and in what order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LookupIpStrategy {
+    /// Only query for A (IPv4) records.
+    Ipv4Only,
+    /// Only query for AAAA (IPv6) records.
+    Ipv6Only,
+    /// Query both A and AAAA concurrently; IPv4 addresses are preferred in the merged result.
+    Ipv4AndIpv6,
+    /// Query AAAA first; only query A if no AAAA records were found.
+    Ipv6thenIpv4,
+    /// Query A first; only query AAAA if no A records were found.
+    Ipv4thenIpv6,
+}
+
+impl Default for LookupIpStrategy {
+    fn default() -> Self {
+        LookupIpStrategy::Ipv4thenIpv6
+    }
+}
+
+/// The Future returned from a `Resolver` when performing an IP lookup, composing one or two
+/// `InnerLookupFuture`s according to the requested `LookupIpStrategy`.
+pub struct LookupIpFuture(Box<Future<Item = Lookup, Error = ResolveError>>);
+
+impl LookupIpFuture {
+    /// Perform an IP lookup from a set of names, following `strategy` to decide which record
+    /// types to query and how to combine/prioritize the results.
+    pub(crate) fn lookup<C: ClientHandle + 'static>(
+        names: Vec<Name>,
+        strategy: LookupIpStrategy,
+        client_cache: &mut DnsLru<C>,
+    ) -> Self {
+        let future = match strategy {
+            LookupIpStrategy::Ipv4Only => {
+                Box::new(InnerLookupFuture::lookup(names, RecordType::A, client_cache))
+                    as Box<Future<Item = Lookup, Error = ResolveError>>
+            }
+            LookupIpStrategy::Ipv6Only => Box::new(InnerLookupFuture::lookup(
+                names,
+                RecordType::AAAA,
+                client_cache,
+            )),
+            LookupIpStrategy::Ipv4AndIpv6 => {
+                let ipv4 = InnerLookupFuture::lookup(names.clone(), RecordType::A, client_cache);
+                let ipv6 = InnerLookupFuture::lookup(names, RecordType::AAAA, client_cache);
+                merge(ipv4, ipv6)
+            }
+            LookupIpStrategy::Ipv4thenIpv6 => {
+                sequential(names, RecordType::A, RecordType::AAAA, client_cache)
+            }
+            LookupIpStrategy::Ipv6thenIpv4 => {
+                sequential(names, RecordType::AAAA, RecordType::A, client_cache)
+            }
+        };
+
+        LookupIpFuture(future)
+    }
+}
+
+impl Future for LookupIpFuture {
+    type Item = Lookup;
+    type Error = ResolveError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.0.poll()
+    }
+}
+
+/// Fires both `preferred` and `other` concurrently and merges their results, `preferred`
+/// first, so it's the first family seen in the merged `LookupIter`. Neither family failing or
+/// coming back empty fails the overall lookup unless both do.
+fn merge<C: ClientHandle + 'static>(
+    preferred: InnerLookupFuture<C>,
+    other: InnerLookupFuture<C>,
+) -> Box<Future<Item = Lookup, Error = ResolveError>> {
+    Box::new(
+        preferred
+            .then(|result| Ok(result.ok()))
+            .join(other.then(|result| Ok(result.ok())))
+            .and_then(|results: (Option<Lookup>, Option<Lookup>)| match results {
+                (Some(preferred), Some(other)) => Ok(preferred.append(other)),
+                (Some(preferred), None) => Ok(preferred),
+                (None, Some(other)) => Ok(other),
+                (None, None) => Err(
+                    ResolveErrorKind::Message(
+                        "no records found for either address family".to_string(),
+                    ).into(),
+                ),
+            }),
+    )
+}
+
+/// Queries `first_type` first; only issues the `second_type` query if the first comes back
+/// with no usable records (empty or an error).
+fn sequential<C: ClientHandle + 'static>(
+    names: Vec<Name>,
+    first_type: RecordType,
+    second_type: RecordType,
+    client_cache: &mut DnsLru<C>,
+) -> Box<Future<Item = Lookup, Error = ResolveError>> {
+    let mut second_client_cache = client_cache.clone();
+    let second_names = names.clone();
+
+    Box::new(
+        InnerLookupFuture::lookup(names, first_type, client_cache).or_else(move |_| {
+            InnerLookupFuture::lookup(second_names, second_type, &mut second_client_cache)
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+    use futures::Future;
+
+    use trust_dns::error::ClientResult;
+    use trust_dns::op::Message;
+    use trust_dns::rr::{Name, RData, Record, RecordType};
+
+    use lookup::tests::{empty, mock, v4_message};
+    use lru::DnsLru;
+
+    use super::*;
+
+    fn v6_message() -> ClientResult<Message> {
+        let mut message = Message::new();
+        message.insert_answers(vec![
+            Record::from_rdata(
+                Name::root(),
+                86400,
+                RecordType::AAAA,
+                RData::AAAA(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)),
+            ),
+        ]);
+        Ok(message)
+    }
+
+    #[test]
+    fn test_ipv4_then_ipv6_short_circuits_on_first_family() {
+        let lookup = LookupIpFuture::lookup(
+            vec![Name::root()],
+            LookupIpStrategy::Ipv4thenIpv6,
+            &mut DnsLru::new(0, mock(vec![v4_message()])),
+        ).wait()
+            .unwrap();
+
+        assert_eq!(
+            lookup
+                .iter()
+                .filter_map(|r| r.to_ip_addr())
+                .collect::<Vec<IpAddr>>(),
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+    }
+
+    #[test]
+    fn test_ipv6_then_ipv4_falls_back_when_ipv6_is_empty() {
+        // popped in reverse: the AAAA attempt sees the empty message first, then falls back
+        // to the A query, which sees the v4 message.
+        let lookup = LookupIpFuture::lookup(
+            vec![Name::root()],
+            LookupIpStrategy::Ipv6thenIpv4,
+            &mut DnsLru::new(0, mock(vec![v4_message(), empty()])),
+        ).wait()
+            .unwrap();
+
+        assert_eq!(
+            lookup
+                .iter()
+                .filter_map(|r| r.to_ip_addr())
+                .collect::<Vec<IpAddr>>(),
+            vec![IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1))]
+        );
+    }
+
+    #[test]
+    fn test_merge_prefers_first_family_but_falls_back_when_it_is_empty() {
+        let ipv4 = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::A,
+            &mut DnsLru::new(0, mock(vec![empty()])),
+        );
+        let ipv6 = InnerLookupFuture::lookup(
+            vec![Name::root()],
+            RecordType::AAAA,
+            &mut DnsLru::new(0, mock(vec![v6_message()])),
+        );
+
+        let lookup = merge(ipv4, ipv6).wait().unwrap();
+
+        assert_eq!(
+            lookup
+                .iter()
+                .filter_map(|r| r.to_ip_addr())
+                .collect::<Vec<IpAddr>>(),
+            vec![IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1))]
+        );
+    }
+}
@@ -0,0 +1,183 @@
+
+// Copyright 2015-2017 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A small TTL cache sitting in front of a `ClientHandle`, responsible for turning a raw
+//! `Message` response into either a `Lookup` or a typed negative-response error.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+
+use trust_dns::client::ClientHandle;
+use trust_dns::op::{Message, Query};
+use trust_dns::rr::{RData, Record, RecordType};
+
+use error::NoRecordsFound;
+use lookup::Lookup;
+
+/// What `classify` decided about a response, together with how long it's good for.
+enum Classified {
+    Found(Lookup, u32),
+    NotFound(NoRecordsFound, u32),
+}
+
+/// A cache entry: either the `Lookup` a query resolved to, or the fact that it didn't,
+/// either way good until `expires`.
+#[derive(Clone)]
+enum CacheEntry {
+    Found(Lookup),
+    NotFound(NoRecordsFound),
+}
+
+/// A cloneable, shared cache of `Query` -> result entries, bounded to `max_size` entries.
+///
+/// Wraps a `ClientHandle`, classifying every response before it's handed back: answers of the
+/// requested type (or a CNAME/DNAME alias) become a `Lookup`, cached for the minimum TTL among
+/// those records; an empty answer section is inspected for `NXDomain` vs `NoData` and any
+/// authority `SOA` minimum TTL, and is itself cached (negative caching) for that long, so a
+/// repeat query for a name that doesn't exist doesn't re-hit the wire.
+pub struct DnsLru<C: ClientHandle> {
+    client: C,
+    max_size: usize,
+    cache: Arc<Mutex<HashMap<Query, (CacheEntry, Instant)>>>,
+}
+
+impl<C: ClientHandle> Clone for DnsLru<C> {
+    fn clone(&self) -> Self {
+        DnsLru {
+            client: self.client.clone(),
+            max_size: self.max_size,
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+impl<C: ClientHandle> DnsLru<C> {
+    /// Creates a new cache fronting `client`, holding at most `max_size` entries. A `max_size`
+    /// of `0` disables caching: every lookup is forwarded to `client`.
+    pub fn new(max_size: usize, client: C) -> Self {
+        DnsLru {
+            client,
+            max_size,
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Performs `query`, consulting (and populating) the cache, classifying the raw `Message`
+    /// into a `Lookup` or a `NoRecordsFound` error.
+    pub(crate) fn lookup(&mut self, query: Query) -> Box<Future<Item = Lookup, Error = io::Error>> {
+        if let Some(entry) = self.cached(&query) {
+            return Box::new(future::result(to_result(entry)));
+        }
+
+        let mut message = Message::new();
+        message.add_query(query.clone());
+
+        let cache = Arc::clone(&self.cache);
+        let max_size = self.max_size;
+        let query_for_cache = query.clone();
+
+        Box::new(self.client.send(message).map_err(io::Error::from).and_then(
+            move |response| {
+                let (entry, ttl) = match classify(&query_for_cache, &response) {
+                    Classified::Found(lookup, ttl) => (CacheEntry::Found(lookup), ttl),
+                    Classified::NotFound(no_records, ttl) => {
+                        (CacheEntry::NotFound(no_records), ttl)
+                    }
+                };
+                insert(&cache, max_size, query_for_cache, entry.clone(), ttl);
+                to_result(entry)
+            },
+        ))
+    }
+
+    fn cached(&self, query: &Query) -> Option<CacheEntry> {
+        let mut cache = self.cache.lock().unwrap();
+        match cache.get(query) {
+            Some(&(ref entry, expires)) if expires > Instant::now() => Some(entry.clone()),
+            Some(_) => {
+                cache.remove(query);
+                None
+            }
+            None => None,
+        }
+    }
+}
+
+fn to_result(entry: CacheEntry) -> Result<Lookup, io::Error> {
+    match entry {
+        CacheEntry::Found(lookup) => Ok(lookup),
+        CacheEntry::NotFound(no_records) => Err(io::Error::new(io::ErrorKind::Other, no_records)),
+    }
+}
+
+fn insert(
+    cache: &Arc<Mutex<HashMap<Query, (CacheEntry, Instant)>>>,
+    max_size: usize,
+    query: Query,
+    entry: CacheEntry,
+    ttl: u32,
+) {
+    if max_size == 0 {
+        return;
+    }
+
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= max_size {
+        return;
+    }
+    cache.insert(query, (entry, Instant::now() + Duration::from_secs(ttl as u64)));
+}
+
+/// Used when a negative response carries no SOA (and thus no minimum TTL) to cache against;
+/// kept short since this is only a fallback.
+const DEFAULT_NEGATIVE_TTL: u32 = 60;
+
+/// Classifies a raw `Message` response to `query` into either the matching `Lookup` (cached
+/// for the minimum TTL among the records that satisfied it) or the typed negative-response
+/// error (cached for the SOA minimum TTL, or `DEFAULT_NEGATIVE_TTL` if the response didn't
+/// carry one), the way `InnerLookupFuture` expects.
+fn classify(query: &Query, response: &Message) -> Classified {
+    let records: Vec<&Record> = response
+        .answers()
+        .iter()
+        .filter(|record| {
+            record.rr_type() == query.query_type() || record.rr_type() == RecordType::CNAME
+                || record.rr_type() == RecordType::DNAME
+        })
+        .collect();
+
+    if !records.is_empty() {
+        let ttl = records.iter().map(|record| record.ttl()).min().unwrap();
+        let rdatas = records.into_iter().map(|record| record.rdata().clone()).collect();
+        return Classified::Found(Lookup::new(Arc::new(rdatas)), ttl);
+    }
+
+    let soa_ttl = soa_min_ttl(response);
+    let no_records = NoRecordsFound {
+        query: query.clone(),
+        response_code: response.response_code(),
+        soa_ttl,
+    };
+    Classified::NotFound(no_records, soa_ttl.unwrap_or(DEFAULT_NEGATIVE_TTL))
+}
+
+/// The authority section's `SOA` minimum TTL, if present, suitable for negative caching.
+fn soa_min_ttl(message: &Message) -> Option<u32> {
+    message
+        .name_servers()
+        .iter()
+        .filter_map(|record| match *record.rdata() {
+            RData::SOA(ref soa) => Some(soa.minimum()),
+            _ => None,
+        })
+        .next()
+}
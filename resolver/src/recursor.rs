@@ -0,0 +1,494 @@
+
+// Copyright 2015-2017 Benjamin Fry <benjaminfry@me.com>
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! A recursive resolver: rather than forwarding every query to a fixed set of name servers
+//! like `NameServerPool` does, this walks delegations starting from a set of root hints,
+//! following `NS` referrals down to an authoritative answer.
+
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+use futures::{future, Future};
+
+use trust_dns::client::ClientHandle;
+use trust_dns::error::{ClientError, ClientErrorKind};
+use trust_dns::op::{Message, Query, ResponseCode};
+use trust_dns::rr::{Name, RData, Record, RecordType};
+
+/// A well-known root (or other bootstrap) server to start iterative resolution from.
+#[derive(Debug, Clone)]
+pub struct RootHint {
+    pub name: Name,
+    pub addr: IpAddr,
+}
+
+/// Opens a `ClientHandle` for talking directly to a specific name server, so the recursor
+/// can follow a delegation to whatever address it was just referred to, rather than being
+/// limited to a fixed, pre-configured pool of servers.
+pub trait NameServerConnect: Send + Sync {
+    fn connect(&self, addr: IpAddr) -> Box<ClientHandle>;
+}
+
+/// Maximum number of steps (delegations followed, failovers to sibling servers and glue
+/// lookups) for a single query before giving up; guards
+/// against self-referential or otherwise pathological delegation chains.
+const MAX_DELEGATION_DEPTH: usize = 30;
+
+/// The set of nameservers known (or believed) to be authoritative for a zone.
+#[derive(Clone, Debug, Default)]
+struct Delegation {
+    servers: Vec<(Name, Option<IpAddr>)>,
+}
+
+impl Delegation {
+    fn from_roots(roots: &[RootHint]) -> Self {
+        Delegation {
+            servers: roots
+                .iter()
+                .map(|root| (root.name.clone(), Some(root.addr)))
+                .collect(),
+        }
+    }
+}
+
+/// Caches the zone -> nameserver delegation discovered while walking the hierarchy, so that
+/// repeat lookups under the same zone don't need to re-walk from the root every time.
+#[derive(Clone, Default)]
+struct DelegationCache {
+    cache: Arc<Mutex<HashMap<Name, Delegation>>>,
+}
+
+impl DelegationCache {
+    /// Returns the closest known delegation for `name`, i.e. the cached zone whose name is
+    /// the longest suffix of `name`.
+    fn closest(&self, name: &Name) -> Option<(Name, Delegation)> {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .iter()
+            .filter(|&(zone, _)| zone.zone_of(name))
+            .max_by_key(|&(zone, _)| zone.num_labels())
+            .map(|(zone, delegation)| (zone.clone(), delegation.clone()))
+    }
+
+    fn insert(&self, zone: Name, delegation: Delegation) {
+        self.cache.lock().unwrap().insert(zone, delegation);
+    }
+}
+
+/// A `ClientHandle` that resolves queries recursively, from root hints down to an
+/// authoritative answer, instead of forwarding them to a fixed pool of name servers.
+#[derive(Clone)]
+pub struct RecursorHandle {
+    roots: Vec<RootHint>,
+    connector: Arc<NameServerConnect>,
+    delegations: DelegationCache,
+}
+
+impl RecursorHandle {
+    /// Creates a new recursive resolver seeded with `roots`, using `connector` to open
+    /// connections to whichever server the current delegation step points at.
+    pub fn new(roots: Vec<RootHint>, connector: Arc<NameServerConnect>) -> Self {
+        RecursorHandle {
+            roots,
+            connector,
+            delegations: DelegationCache::default(),
+        }
+    }
+}
+
+impl ClientHandle for RecursorHandle {
+    fn send(&mut self, message: Message) -> Box<Future<Item = Message, Error = ClientError>> {
+        let query = match message.queries().first() {
+            Some(query) => query.original().clone(),
+            None => {
+                return Box::new(future::err(
+                    ClientErrorKind::Message("no query in message").into(),
+                ))
+            }
+        };
+
+        resolve(self.clone(), query, 0)
+    }
+}
+
+/// Iteratively resolves `query`, walking delegations one zone cut at a time. Each step boxes
+/// its continuation, so the recursion is bounded by `MAX_DELEGATION_DEPTH` rather than by the
+/// Rust type system.
+fn resolve(
+    state: RecursorHandle,
+    query: Query,
+    depth: usize,
+) -> Box<Future<Item = Message, Error = ClientError>> {
+    if depth >= MAX_DELEGATION_DEPTH {
+        return Box::new(future::err(
+            ClientErrorKind::Message("maximum delegation depth exceeded").into(),
+        ));
+    }
+
+    let (zone, delegation) = state
+        .delegations
+        .closest(query.name())
+        .unwrap_or_else(|| (Name::root(), Delegation::from_roots(&state.roots)));
+
+    resolve_via(state, zone, delegation, query, depth)
+}
+
+/// Queries the first server in `delegation` that has (or can resolve) a usable address,
+/// resolving missing glue recursively before proceeding.
+fn resolve_via(
+    state: RecursorHandle,
+    zone: Name,
+    delegation: Delegation,
+    query: Query,
+    depth: usize,
+) -> Box<Future<Item = Message, Error = ClientError>> {
+    if depth >= MAX_DELEGATION_DEPTH {
+        return Box::new(future::err(
+            ClientErrorKind::Message("maximum delegation depth exceeded").into(),
+        ));
+    }
+
+    let mut servers = delegation.servers.clone();
+    let (ns_name, addr) = match servers.pop() {
+        Some(server) => server,
+        None => {
+            return Box::new(future::err(
+                ClientErrorKind::Message("no nameservers available for delegation").into(),
+            ))
+        }
+    };
+    let remaining = Delegation { servers };
+
+    match addr {
+        Some(addr) => {
+            // A single server erroring (a transport failure, say) shouldn't fail the whole
+            // resolution when sibling servers are available; fail over to `remaining`.
+            let (retry_state, retry_zone, retry_query) = (state.clone(), zone.clone(), query.clone());
+            Box::new(
+                query_server(state, zone, query, depth, addr).or_else(move |_| {
+                    resolve_via(retry_state, retry_zone, remaining, retry_query, depth + 1)
+                }),
+            )
+        }
+        None => {
+            // No glue was provided for this nameserver. If it's in-bailiwick (the common
+            // missing-glue case, e.g. zone `example.com` served by `ns1.example.com`),
+            // resolving its address would just recurse back into this same delegation,
+            // so skip straight to the other servers instead of resolving it.
+            if ns_name.zone_of(&zone) || zone.zone_of(&ns_name) {
+                return resolve_via(state, zone, remaining, query, depth + 1);
+            }
+
+            let glue_query = Query::query(ns_name, RecordType::A);
+            Box::new(resolve(state.clone(), glue_query, depth + 1).and_then(
+                move |response| {
+                    match first_address(&response) {
+                        Some(addr) => query_server(state, zone, query, depth, addr),
+                        None => resolve_via(state, zone, remaining, query, depth + 1),
+                    }
+                },
+            ))
+        }
+    }
+}
+
+fn query_server(
+    mut state: RecursorHandle,
+    zone: Name,
+    query: Query,
+    depth: usize,
+    addr: IpAddr,
+) -> Box<Future<Item = Message, Error = ClientError>> {
+    let mut client = state.connector.connect(addr);
+    let mut message = Message::new();
+    message.add_query(query.clone());
+
+    Box::new(client.send(message).and_then(move |response| {
+        if has_answer(&response, query.record_type()) || is_authoritative_nxdomain(&response) {
+            return Box::new(future::ok(response)) as Box<Future<Item = Message, Error = ClientError>>;
+        }
+
+        match next_delegation(&response) {
+            Some((next_zone, next_delegation)) => {
+                state.delegations.insert(next_zone.clone(), next_delegation.clone());
+                resolve_via(state, next_zone, next_delegation, query, depth + 1)
+            }
+            // no referral to follow and no answer: this is as far as the delegation goes
+            None => Box::new(future::ok(response)),
+        }
+    }))
+}
+
+fn has_answer(message: &Message, record_type: RecordType) -> bool {
+    message
+        .answers()
+        .iter()
+        .any(|record| record.rr_type() == record_type)
+}
+
+fn is_authoritative_nxdomain(message: &Message) -> bool {
+    message.response_code() == ResponseCode::NXDomain
+}
+
+/// Picks out the next zone's nameservers (and any glue addresses) from the authority and
+/// additional sections of a referral response.
+fn next_delegation(message: &Message) -> Option<(Name, Delegation)> {
+    let zone = message
+        .name_servers()
+        .iter()
+        .map(|record| record.name().clone())
+        .next()?;
+
+    let servers = message
+        .name_servers()
+        .iter()
+        .filter(|record| record.rr_type() == RecordType::NS)
+        .filter_map(|record| match *record.rdata() {
+            RData::NS(ref name) => Some(name.clone()),
+            _ => None,
+        })
+        .map(|ns_name| {
+            let addr = message
+                .additionals()
+                .iter()
+                .filter(|record| record.name() == &ns_name)
+                .filter_map(glue_address)
+                .next();
+            (ns_name, addr)
+        })
+        .collect::<Vec<_>>();
+
+    if servers.is_empty() {
+        None
+    } else {
+        Some((zone, Delegation { servers }))
+    }
+}
+
+fn glue_address(record: &Record) -> Option<IpAddr> {
+    match *record.rdata() {
+        RData::A(ip) => Some(IpAddr::V4(ip)),
+        RData::AAAA(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    }
+}
+
+fn first_address(message: &Message) -> Option<IpAddr> {
+    message.answers().iter().filter_map(glue_address).next()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+    use std::sync::Mutex;
+
+    use futures::Future;
+
+    use trust_dns::error::ClientResult;
+
+    use super::*;
+
+    /// A `ClientHandle` that replies with a canned script of messages, popped in reverse
+    /// order, the same convention used by `lookup::tests::MockClientHandle`.
+    struct MockHandle {
+        messages: Arc<Mutex<Vec<ClientResult<Message>>>>,
+    }
+
+    impl ClientHandle for MockHandle {
+        fn send(&mut self, _: Message) -> Box<Future<Item = Message, Error = ClientError>> {
+            Box::new(future::result(
+                self.messages.lock().unwrap().pop().unwrap_or_else(|| Ok(Message::new())),
+            ))
+        }
+    }
+
+    /// A `NameServerConnect` backed by a fixed map of address -> script, so a test can drive
+    /// exactly which response each hop in a delegation walk gets.
+    struct MockConnector {
+        scripts: HashMap<IpAddr, Arc<Mutex<Vec<ClientResult<Message>>>>>,
+    }
+
+    impl NameServerConnect for MockConnector {
+        fn connect(&self, addr: IpAddr) -> Box<ClientHandle> {
+            let messages = self.scripts
+                .get(&addr)
+                .cloned()
+                .unwrap_or_else(|| Arc::new(Mutex::new(vec![])));
+            Box::new(MockHandle { messages })
+        }
+    }
+
+    fn connector(scripts: Vec<(IpAddr, ClientResult<Message>)>) -> Arc<MockConnector> {
+        let mut map: HashMap<IpAddr, Arc<Mutex<Vec<ClientResult<Message>>>>> = HashMap::new();
+        for (addr, message) in scripts {
+            map.entry(addr).or_insert_with(|| Arc::new(Mutex::new(vec![]))).lock().unwrap().push(
+                message,
+            );
+        }
+        Arc::new(MockConnector { scripts: map })
+    }
+
+    fn referral(zone: Name, ns_name: Name, glue: Option<IpAddr>) -> ClientResult<Message> {
+        let mut message = Message::new();
+        message.insert_name_servers(vec![
+            Record::from_rdata(zone, 3600, RecordType::NS, RData::NS(ns_name.clone())),
+        ]);
+
+        if let Some(addr) = glue {
+            let (rtype, rdata) = match addr {
+                IpAddr::V4(ip) => (RecordType::A, RData::A(ip)),
+                IpAddr::V6(ip) => (RecordType::AAAA, RData::AAAA(ip)),
+            };
+            message.insert_additionals(vec![Record::from_rdata(ns_name, 3600, rtype, rdata)]);
+        }
+
+        Ok(message)
+    }
+
+    fn a_answer(name: Name, ip: Ipv4Addr) -> ClientResult<Message> {
+        let mut message = Message::new();
+        message.insert_answers(vec![Record::from_rdata(name, 3600, RecordType::A, RData::A(ip))]);
+        Ok(message)
+    }
+
+    #[test]
+    fn test_referral_chain_root_to_tld_to_authoritative() {
+        let root_addr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+        let tld_addr = IpAddr::V4(Ipv4Addr::new(2, 2, 2, 2));
+        let auth_addr = IpAddr::V4(Ipv4Addr::new(3, 3, 3, 3));
+
+        let query_name = Name::parse("www.example.com.", None).unwrap();
+        let com_zone = Name::parse("com.", None).unwrap();
+        let example_zone = Name::parse("example.com.", None).unwrap();
+        let ns1_com = Name::parse("ns1.com.", None).unwrap();
+        let ns1_example = Name::parse("ns1.example.com.", None).unwrap();
+
+        let connector = connector(vec![
+            (root_addr, referral(com_zone, ns1_com, Some(tld_addr))),
+            (tld_addr, referral(example_zone, ns1_example, Some(auth_addr))),
+            (auth_addr, a_answer(query_name.clone(), Ipv4Addr::new(127, 0, 0, 1))),
+        ]);
+
+        let roots = vec![
+            RootHint {
+                name: Name::root(),
+                addr: root_addr,
+            },
+        ];
+        let state = RecursorHandle::new(roots, connector);
+
+        let response = resolve(state, Query::query(query_name, RecordType::A), 0)
+            .wait()
+            .unwrap();
+
+        assert!(has_answer(&response, RecordType::A));
+    }
+
+    #[test]
+    fn test_missing_glue_is_resolved_before_querying() {
+        let root_addr = IpAddr::V4(Ipv4Addr::new(9, 9, 9, 9));
+        let glue_addr = IpAddr::V4(Ipv4Addr::new(4, 4, 4, 4));
+
+        let ns_name = Name::parse("ns1.example.net.", None).unwrap();
+        let zone = Name::parse("example.org.", None).unwrap();
+        let query_name = Name::parse("www.example.org.", None).unwrap();
+
+        let connector = connector(vec![
+            (root_addr, a_answer(ns_name.clone(), Ipv4Addr::new(4, 4, 4, 4))),
+            (glue_addr, a_answer(query_name.clone(), Ipv4Addr::new(127, 0, 0, 1))),
+        ]);
+
+        let roots = vec![
+            RootHint {
+                name: Name::root(),
+                addr: root_addr,
+            },
+        ];
+        let state = RecursorHandle::new(roots, connector);
+
+        let delegation = Delegation { servers: vec![(ns_name, None)] };
+        let response = resolve_via(
+            state,
+            zone,
+            delegation,
+            Query::query(query_name, RecordType::A),
+            0,
+        ).wait()
+            .unwrap();
+
+        assert!(has_answer(&response, RecordType::A));
+    }
+
+    #[test]
+    fn test_in_bailiwick_missing_glue_falls_over_to_sibling() {
+        let sibling_addr = IpAddr::V4(Ipv4Addr::new(6, 6, 6, 6));
+
+        let zone = Name::parse("example.com.", None).unwrap();
+        // in-bailiwick: resolving its address would recurse back into this same delegation
+        let ns1 = Name::parse("ns1.example.com.", None).unwrap();
+        let sibling = Name::parse("ns2.example.net.", None).unwrap();
+        let query_name = Name::parse("www.example.com.", None).unwrap();
+
+        let connector = connector(vec![
+            (sibling_addr, a_answer(query_name.clone(), Ipv4Addr::new(127, 0, 0, 1))),
+        ]);
+
+        let state = RecursorHandle::new(vec![], connector);
+
+        // `servers.pop()` is tried last-first, so the in-bailiwick server is attempted before
+        // the sibling with usable glue.
+        let delegation = Delegation {
+            servers: vec![(sibling, Some(sibling_addr)), (ns1, None)],
+        };
+        let response = resolve_via(
+            state,
+            zone,
+            delegation,
+            Query::query(query_name, RecordType::A),
+            0,
+        ).wait()
+            .unwrap();
+
+        assert!(has_answer(&response, RecordType::A));
+    }
+
+    #[test]
+    fn test_glued_server_error_falls_over_to_sibling() {
+        let failing_addr = IpAddr::V4(Ipv4Addr::new(7, 7, 7, 7));
+        let sibling_addr = IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8));
+
+        let zone = Name::parse("example.com.", None).unwrap();
+        let ns1 = Name::parse("ns1.example.com.", None).unwrap();
+        let sibling = Name::parse("ns2.example.com.", None).unwrap();
+        let query_name = Name::parse("www.example.com.", None).unwrap();
+
+        // `failing_addr` is popped (and thus tried) first; it errors outright (a transport
+        // failure), so `resolve_via`'s `.or_else` must retry the sibling instead of giving up.
+        let connector = connector(vec![
+            (failing_addr, Err(ClientErrorKind::Message("transport error").into())),
+            (sibling_addr, a_answer(query_name.clone(), Ipv4Addr::new(127, 0, 0, 1))),
+        ]);
+
+        let state = RecursorHandle::new(vec![], connector);
+        let delegation = Delegation {
+            servers: vec![(sibling, Some(sibling_addr)), (ns1, Some(failing_addr))],
+        };
+        let response = resolve_via(
+            state,
+            zone,
+            delegation,
+            Query::query(query_name, RecordType::A),
+            0,
+        ).wait()
+            .unwrap();
+
+        assert!(has_answer(&response, RecordType::A));
+    }
+}